@@ -1,32 +1,90 @@
 #![forbid(unsafe_code)]
 #![warn(clippy::pedantic, clippy::cargo)]
-#![allow(clippy::unused_async, clippy::unnecessary_wraps)]
+#![allow(
+    clippy::unused_async,
+    clippy::unnecessary_wraps,
+    clippy::multiple_crate_versions
+)]
 
-use axum::extract::State;
-use axum::Error;
+use axum::body::Body;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Query, State};
+use axum::http::Request;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method};
+use axum::middleware::{self, Next};
+use axum::response::Response;
 use axum::{
-    extract::Path, http::StatusCode, response::Html, response::IntoResponse, routing::get, Json,
-    Router,
+    extract::Path, http::StatusCode, response::Html, response::IntoResponse, routing::get,
+    routing::post, Json, Router,
 };
-use clap::{arg, command, Parser};
-use serde::Deserialize;
+#[cfg(feature = "embedded")]
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser};
+use hyper::body::HttpBody;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::{self};
+use std::future::Future;
 use std::net::SocketAddr;
-use std::path::Path as fsPath;
-use std::sync::Arc;
+use std::path::{Path as fsPath, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::watch;
+use tower_http::compression::predicate::{DefaultPredicate, Predicate, SizeAbove};
+use tower_http::set_header::SetResponseHeaderLayer;
 use tower_http::{compression::CompressionLayer, trace::TraceLayer};
 use tracing::log;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+#[allow(clippy::struct_excessive_bools)]
 struct AppState {
     data_dir: String,
-    files: Vec<String>,
+    files: RwLock<Vec<String>>,
+    /// Variant files like `users.b` served for `/api/users?_variant=b`
+    variants: RwLock<HashSet<String>>,
+    masks: HashMap<String, Vec<String>>,
+    started_at: Instant,
+    case_insensitive_routes: bool,
+    preserve_numbers: bool,
+    delays: HashMap<String, Duration>,
+    delay_on_error: Option<Duration>,
+    forced_statuses: HashMap<String, StatusCode>,
+    recent_request_ids: Mutex<VecDeque<(String, Instant)>>,
+    response_template: Option<Value>,
+    wrap_scalars: bool,
+    access_log_format: Option<String>,
+    max_response: Option<u64>,
+    verbose_errors: bool,
+    fixture_format_default: Option<String>,
+    trailing_data_policy: String,
+    encoding: String,
+    sort_keys: bool,
+    throttle: Option<u64>,
+    min_compress_size: u16,
+    mocks: HashMap<String, Mock>,
+    response_headers: Vec<(HeaderName, HeaderValue)>,
+    id_field: String,
+    jsonapi: bool,
+    /// The parsed command line, served at /_config
+    config: Value,
+    #[cfg(feature = "transform")]
+    transform: Option<Transform>,
+    #[cfg(feature = "protobuf")]
+    proto_messages: HashMap<String, prost_reflect::MessageDescriptor>,
+    shutdown: watch::Sender<bool>,
 }
 
 /// Simple program to greet a person
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone, Serialize)]
 #[command(author, version, about, long_about = None)]
+#[allow(clippy::struct_excessive_bools)]
 struct Args {
     /// Port to listen on
     #[arg(short, long, default_value_t = 3000)]
@@ -35,11 +93,294 @@ struct Args {
     /// Path to the folder
     #[arg(short, long, default_value_t = format!("./data"))]
     data_dir: String,
+
+    /// Also serve files starting with a dot, e.g. `.backup.json`
+    #[arg(long)]
+    include_hidden: bool,
+
+    /// Redact fields in responses, e.g. `users.email,users.address.street`
+    #[arg(long, value_delimiter = ',')]
+    mask: Vec<String>,
+
+    /// Match endpoint names regardless of case, e.g. `/api/Users` for `users.json`
+    #[arg(long)]
+    case_insensitive_routes: bool,
+
+    /// Field holding the record id in array collections
+    #[arg(long, default_value_t = format!("id"))]
+    id_field: String,
+
+    /// Serve array collections as JSON:API documents of `{type, id, attributes}` resources
+    #[arg(long)]
+    jsonapi: bool,
+
+    /// Exit at startup when an array collection contains duplicate ids
+    #[arg(long)]
+    fail_on_duplicate_id: bool,
+
+    /// Path the health check is served at, e.g. `/healthz`
+    #[arg(long, default_value_t = format!("/_health_check"))]
+    health_path: String,
+
+    /// Serve ops endpoints like `/_health_check` on this port instead
+    #[arg(long)]
+    admin_port: Option<u16>,
+
+    /// Use the single-line compact log formatter
+    #[arg(long)]
+    compact_logs: bool,
+
+    /// Warn at startup about data files larger than this many bytes
+    #[arg(long)]
+    warn_size: Option<u64>,
+
+    /// Serve numbers exactly as written instead of round-tripping them through f64
+    #[arg(long)]
+    preserve_numbers: bool,
+
+    /// Only serve an endpoint when an env var is truthy, e.g. `BETA=users`
+    #[arg(long)]
+    enable_if_env: Vec<String>,
+
+    /// Per-endpoint response delay in milliseconds, e.g. `users=500,orders=1000`
+    #[arg(long, value_delimiter = ',')]
+    delay_collection: Vec<String>,
+
+    /// Answer GET requests to an endpoint with this error status, e.g. `users=503`
+    #[arg(long, value_delimiter = ',')]
+    force_status: Vec<String>,
+
+    /// Wait this many milliseconds before sending a 4xx/5xx /api response
+    #[arg(long)]
+    delay_on_error: Option<u64>,
+
+    /// Canned responses keyed by `METHOD /path`, checked before the data files
+    #[arg(long)]
+    mock: Option<String>,
+
+    /// Wrap json responses in an envelope, e.g. `{"ok":true,"data":{data},"error":{error}}`
+    #[arg(long)]
+    response_template: Option<String>,
+
+    /// Don't serve the html page at `/`, it falls through to the 404 handler
+    #[arg(long)]
+    no_root: bool,
+
+    /// Path the endpoint listing is served at, `/` replaces the html page
+    #[arg(long, default_value_t = format!("/api"))]
+    index_endpoint: String,
+
+    /// Header added to every response, e.g. `X-Powered-By: json-server-rs`, repeatable
+    #[arg(long)]
+    response_header: Vec<String>,
+
+    /// Only compress responses larger than this many bytes
+    #[arg(long, default_value_t = 32)]
+    min_compress_size: u16,
+
+    /// Limit /api response bodies to this many bytes per second
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    throttle: Option<u64>,
+
+    /// Number of tokio worker threads, defaults to the number of CPUs
+    #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    workers: Option<usize>,
+
+    /// Serve files holding a bare scalar as a one-element array
+    #[arg(long)]
+    wrap_scalars: bool,
+
+    /// Log level used when `RUST_LOG` isn't set
+    #[arg(long, default_value = "debug", value_parser = ["trace", "debug", "info", "warn", "error"])]
+    log_level: String,
+
+    /// Log one line per request, e.g. `{method} {path} {status} {latency_ms}ms`
+    #[arg(long)]
+    access_log_format: Option<String>,
+
+    /// Refuse to send response bodies larger than this many bytes
+    #[arg(long)]
+    max_response: Option<u64>,
+
+    /// Enable `POST /_shutdown` to stop the server, meant for CI harnesses
+    #[arg(long)]
+    allow_remote_shutdown: bool,
+
+    /// Enable `GET /_config`, which shows paths and commands from the command line
+    #[arg(long)]
+    expose_config: bool,
+
+    /// Shell command to run once the server is listening
+    #[arg(long)]
+    on_start: Option<String>,
+
+    /// Serve `v1/`, `v2/`, ... subdirectories as `/api/v1/*`, `/api/v2/*`, ...
+    #[arg(long)]
+    api_versions: bool,
+
+    /// Parse every data file at startup, exiting on errors and warning about
+    /// collections that not all features can be used with
+    #[arg(long)]
+    strict: bool,
+
+    /// Validate the options and parse every data file like --strict, listing
+    /// each file's status, then exit without starting the server
+    #[arg(long)]
+    check: bool,
+
+    /// Run every response through a WASM module, needs the `transform` feature
+    #[arg(long)]
+    transform: Option<String>,
+
+    /// `FileDescriptorSet` to encode responses with when protobuf is asked for,
+    /// needs the `protobuf` feature
+    #[arg(long)]
+    proto_descriptor: Option<String>,
+
+    /// Message type of an endpoint's records in --proto-descriptor, e.g. `users=example.User`
+    #[arg(long, value_delimiter = ',')]
+    proto_message: Vec<String>,
+
+    /// Close idle keep-alive connections after this many seconds, 0 turns keep-alive off
+    #[arg(long)]
+    keep_alive: Option<u64>,
+
+    /// Also serve files without an extension, parsing them as this format
+    #[arg(long, value_parser = ["json", "toml", "yaml"])]
+    fixture_format_default: Option<String>,
+
+    /// What to do with more json after the first value in a file: `error`,
+    /// serve only the `first` value, or serve all values as an `array`
+    #[arg(long, default_value_t = format!("error"), value_parser = ["error", "first", "array"])]
+    trailing_data_policy: String,
+
+    /// Character encoding of the data files
+    #[arg(long, default_value_t = format!("utf8"), value_parser = ["utf8", "utf16", "latin1"])]
+    encoding: String,
+
+    /// Sort object keys alphabetically in responses instead of keeping the file's order
+    #[arg(long)]
+    sort_keys: bool,
+
+    /// Include the file path and parse location in 500 responses
+    #[arg(long)]
+    verbose_errors: bool,
+}
+
+fn main() {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    #[cfg(feature = "embedded")]
+    let _cleanup = EmbeddedCleanup;
+    #[cfg(feature = "embedded")]
+    let embedded = (matches.value_source("data_dir") != Some(ValueSource::CommandLine))
+        .then(extract_embedded_data)
+        .flatten();
+    #[cfg(feature = "embedded")]
+    let args = match &embedded {
+        Some(dir) => Args {
+            data_dir: dir.clone(),
+            ..args
+        },
+        None => args,
+    };
+
+    // built by hand instead of #[tokio::main] so --workers applies before startup
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(workers) = args.workers {
+        runtime.worker_threads(workers);
+    }
+    runtime
+        .enable_all()
+        .build()
+        .expect("Can't build tokio runtime")
+        .block_on(run(args));
+}
+
+// exit right away, without leaving extracted embedded data behind
+fn exit(code: i32) -> ! {
+    #[cfg(feature = "embedded")]
+    remove_embedded_data();
+    std::process::exit(code)
+}
+
+#[cfg(feature = "embedded")]
+static EMBEDDED_DATA: include_dir::Dir = include_dir::include_dir!("$CARGO_MANIFEST_DIR/data");
+
+// where extract_embedded_data put the data, removed again on the way out
+#[cfg(feature = "embedded")]
+static EMBEDDED_DIR: std::sync::OnceLock<PathBuf> = std::sync::OnceLock::new();
+
+// write the data baked in by the `embedded` feature to a new temporary
+// directory and serve it from there like any other data_dir; the name is
+// random and the directory must not exist yet, so nobody can plant the
+// fixtures that get served
+#[cfg(feature = "embedded")]
+fn extract_embedded_data() -> Option<String> {
+    use std::hash::{BuildHasher, Hasher};
+
+    if EMBEDDED_DATA.entries().is_empty() {
+        return None;
+    }
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    let dir = std::env::temp_dir().join(format!(
+        "json-server-rs-{}-{random:016x}",
+        std::process::id()
+    ));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    if let Err(e) = builder.create(&dir) {
+        println!("can't create {} for the embedded data: {e}", dir.display());
+        exit(1);
+    }
+    let _ = EMBEDDED_DIR.set(dir.clone());
+    if let Err(e) = EMBEDDED_DATA.extract(&dir) {
+        println!("can't extract embedded data to {}: {e}", dir.display());
+        exit(1);
+    }
+    Some(dir.to_string_lossy().into_owned())
+}
+
+#[cfg(feature = "embedded")]
+fn remove_embedded_data() {
+    if let Some(dir) = EMBEDDED_DIR.get() {
+        let _ = fs::remove_dir_all(dir);
+    }
+}
+
+// removes the extracted data when main returns or a panic unwinds it
+#[cfg(feature = "embedded")]
+struct EmbeddedCleanup;
+
+#[cfg(feature = "embedded")]
+impl Drop for EmbeddedCleanup {
+    fn drop(&mut self) {
+        remove_embedded_data();
+    }
+}
+
+// RUST_LOG wins over --log-level
+fn init_tracing(args: &Args) {
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+                format!("json_server_rs={0},tower_http={0}", args.log_level).into()
+            }),
+        )
+        .with((!args.compact_logs).then(tracing_subscriber::fmt::layer))
+        .with(
+            args.compact_logs
+                .then(|| tracing_subscriber::fmt::layer().compact()),
+        )
+        .init();
 }
 
-#[tokio::main]
-async fn main() {
-    let args = Args::parse();
+async fn run(args: Args) {
+    init_tracing(&args);
 
     // get the data_dir from the command line
     let data_dir = fsPath::new(&args.data_dir).to_str().unwrap().to_string();
@@ -54,17 +395,20 @@ async fn main() {
         tracing::warn!("data_dir does not exist: {data_dir}");
         log::warn!("data_dir does not exist: {data_dir}");
         println!("data_dir does not exist: {data_dir}");
-        std::process::exit(1);
+        exit(1);
     }
 
-    // check if the folder contains .json files
-    let files = get_json_files(data_dir.clone()).expect("Can't get json files");
+    // check if the folder contains data files
+    let files = discover_files(&args, &data_dir).unwrap_or_else(|e| {
+        println!("can't read data_dir {data_dir}: {e}");
+        exit(1);
+    });
 
     if files.is_empty() {
         tracing::warn!("data_dir does not contain any json files");
         log::warn!("data_dir does not contain any json files");
         println!("data_dir does not contain any json files");
-        std::process::exit(1);
+        exit(1);
     } else {
         tracing::debug!("data_dir contains json files: {files:?}");
     }
@@ -76,108 +420,2702 @@ async fn main() {
         data_dir
     };
 
-    let shared_state = Arc::new(AppState { data_dir, files });
+    check_data_files(&args, &data_dir, &files);
+    let (files, variants) = split_variants(&files);
 
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "json-server-rs=debug,tower_http=debug".into()),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+    #[cfg(not(feature = "transform"))]
+    load_transform(&args);
+    #[cfg(not(feature = "protobuf"))]
+    load_proto_messages(&args);
+    check_route_paths(&args);
+
+    if let Some(format) = &args.access_log_format {
+        if let Err(e) = check_placeholders(format, &ACCESS_LOG_FIELDS) {
+            println!("invalid --access-log-format: {e}");
+            exit(1);
+        }
+    }
+
+    let shared_state = Arc::new(app_state(&args, data_dir, files, variants));
+    if args.check {
+        println!("configuration ok");
+        return;
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(args.clone(), shared_state.clone()));
+
+    serve(&args, shared_state).await;
+}
+
+// everything handlers share, built from the command line and the first scan
+fn app_state(
+    args: &Args,
+    data_dir: String,
+    files: Vec<String>,
+    variants: HashSet<String>,
+) -> AppState {
+    let response_template = args.response_template.as_deref().map(|template| {
+        parse_response_template(template).unwrap_or_else(|e| {
+            println!("invalid --response-template: {e}");
+            exit(1);
+        })
+    });
+
+    AppState {
+        data_dir,
+        files: RwLock::new(files),
+        variants: RwLock::new(variants),
+        masks: parse_masks(args),
+        started_at: Instant::now(),
+        case_insensitive_routes: args.case_insensitive_routes,
+        preserve_numbers: args.preserve_numbers,
+        delays: parse_delays(args),
+        delay_on_error: args.delay_on_error.map(Duration::from_millis),
+        forced_statuses: parse_forced_statuses(args),
+        recent_request_ids: Mutex::new(VecDeque::new()),
+        response_template,
+        wrap_scalars: args.wrap_scalars,
+        access_log_format: args.access_log_format.clone(),
+        max_response: args.max_response,
+        verbose_errors: args.verbose_errors,
+        fixture_format_default: args.fixture_format_default.clone(),
+        trailing_data_policy: args.trailing_data_policy.clone(),
+        encoding: args.encoding.clone(),
+        sort_keys: args.sort_keys,
+        throttle: args.throttle,
+        min_compress_size: args.min_compress_size,
+        mocks: args.mock.as_deref().map(load_mocks).unwrap_or_default(),
+        response_headers: parse_response_headers(args),
+        id_field: args.id_field.clone(),
+        jsonapi: args.jsonapi,
+        config: effective_config(args),
+        #[cfg(feature = "transform")]
+        transform: load_transform(args),
+        #[cfg(feature = "protobuf")]
+        proto_messages: load_proto_messages(args),
+        shutdown: watch::channel(false).0,
+    }
+}
+
+// group the masked field paths by the collection they apply to
+fn parse_masks(args: &Args) -> HashMap<String, Vec<String>> {
+    let mut masks: HashMap<String, Vec<String>> = HashMap::new();
+    for mask in &args.mask {
+        let Some((file, field)) = mask.split_once('.') else {
+            println!("invalid mask, expected <file>.<field>: {mask}");
+            exit(1);
+        };
+        masks
+            .entry(file.to_string())
+            .or_default()
+            .push(field.to_string());
+    }
+    masks
+}
+
+// split `Name: value` pairs for --response-header
+fn parse_response_headers(args: &Args) -> Vec<(HeaderName, HeaderValue)> {
+    args.response_header
+        .iter()
+        .map(|header| {
+            let parsed = header.split_once(':').and_then(|(name, value)| {
+                Some((
+                    HeaderName::from_bytes(name.trim().as_bytes()).ok()?,
+                    HeaderValue::from_str(value.trim()).ok()?,
+                ))
+            });
+            parsed.unwrap_or_else(|| {
+                println!("invalid --response-header, expected <Name>: <value>: {header}");
+                exit(1);
+            })
+        })
+        .collect()
+}
+
+// map endpoints to their --delay-collection delay
+fn parse_delays(args: &Args) -> HashMap<String, Duration> {
+    let mut delays = HashMap::new();
+    for delay in &args.delay_collection {
+        let Some((file, Ok(millis))) = delay
+            .split_once('=')
+            .map(|(file, millis)| (file, millis.parse::<u64>()))
+        else {
+            println!("invalid --delay-collection, expected <endpoint>=<ms>: {delay}");
+            exit(1);
+        };
+        delays.insert(file.to_string(), Duration::from_millis(millis));
+    }
+    delays
+}
+
+// map endpoints to their --force-status error status
+fn parse_forced_statuses(args: &Args) -> HashMap<String, StatusCode> {
+    let mut forced = HashMap::new();
+    for rule in &args.force_status {
+        let Some((file, Some(status))) = rule
+            .split_once('=')
+            .map(|(file, status)| (file, error_status(status)))
+        else {
+            println!("invalid --force-status, expected <endpoint>=<4xx|5xx>: {rule}");
+            exit(1);
+        };
+        forced.insert(file.to_string(), status);
+    }
+    forced
+}
+
+// parse a status code that is a client or server error
+fn error_status(status: &str) -> Option<StatusCode> {
+    status
+        .parse::<u16>()
+        .ok()
+        .and_then(|code| StatusCode::from_u16(code).ok())
+        .filter(|status| status.is_client_error() || status.is_server_error())
+}
+
+// find the endpoints to serve, honouring --include-hidden and --enable-if-env
+fn discover_files(args: &Args, data_dir: &str) -> std::io::Result<Vec<String>> {
+    let extensionless = args.fixture_format_default.is_some();
+    let mut files = get_json_files(data_dir, args.include_hidden, extensionless)?;
+
+    // versioned endpoints are named after their folder, e.g. `v1/users`
+    if args.api_versions {
+        for version in get_version_dirs(data_dir)? {
+            let version_dir = format!("{data_dir}/{version}");
+            let mut endpoints = get_json_files(&version_dir, args.include_hidden, extensionless)?;
+            // `/api/v1/search` and `/api/v1/_example` are routes of their own
+            endpoints.retain(|file| {
+                let reserved = file == "search" || file == "_example";
+                if reserved {
+                    tracing::warn!("skipping {version}/{file}, the name is taken by a route");
+                }
+                !reserved
+            });
+            files.extend(
+                endpoints
+                    .into_iter()
+                    .map(|file| format!("{version}/{file}")),
+            );
+        }
+    }
+
+    // drop endpoints whose enabling env var isn't set
+    for rule in &args.enable_if_env {
+        let Some((var, endpoint)) = rule.split_once('=') else {
+            println!("invalid --enable-if-env, expected <VAR>=<endpoint>: {rule}");
+            exit(1);
+        };
+        if !env_is_truthy(var) {
+            tracing::debug!("{endpoint} disabled, {var} is not set");
+            // its variants go too, or they would be served as endpoints of their own
+            files.retain(|file| {
+                let base = file.rsplit_once('.').map(|(base, _)| base);
+                file != endpoint && base != Some(endpoint)
+            });
+        }
+    }
+    Ok(files)
+}
+
+// rescan the data_dir whenever the process receives SIGHUP
+#[cfg(unix)]
+async fn reload_on_sighup(args: Args, state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("Can't listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        match rescan(&args, &state) {
+            Ok((before, after)) => {
+                tracing::info!("SIGHUP: rescanned data_dir, {before} -> {after} endpoints");
+            }
+            Err(e) => {
+                tracing::warn!("SIGHUP: can't rescan data_dir, keeping the current endpoints: {e}");
+            }
+        }
+    }
+}
+
+// swap in a fresh scan of the data_dir, returning the endpoint counts
+// before and after
+#[cfg(unix)]
+fn rescan(args: &Args, state: &AppState) -> std::io::Result<(usize, usize)> {
+    let (files, variants) = split_variants(&discover_files(args, &state.data_dir)?);
+    // always locked files first, then variants, so a request that holds
+    // both sees either the old or the new scan and never a mix
+    let mut current = state.files.write().unwrap();
+    let mut current_variants = state.variants.write().unwrap();
+    let counts = (current.len(), files.len());
+    *current = files;
+    *current_variants = variants;
+    Ok(counts)
+}
+
+// split `users.b` style variant files off the endpoints they belong to
+fn split_variants(files: &[String]) -> (Vec<String>, HashSet<String>) {
+    let (variants, files): (Vec<String>, Vec<String>) = files.iter().cloned().partition(|file| {
+        file.rsplit_once('.')
+            .is_some_and(|(base, _)| files.iter().any(|f| f == base))
+    });
+    (files, variants.into_iter().collect())
+}
+
+// startup diagnostics over the discovered data files
+fn check_data_files(args: &Args, data_dir: &str, files: &[String]) {
+    let options = ParseOptions {
+        default_format: args.fixture_format_default.as_deref(),
+        trailing_data: &args.trailing_data_policy,
+        encoding: &args.encoding,
+    };
+    if let Some(warn_size) = args.warn_size {
+        for file in files {
+            let path = data_file_path(data_dir, file);
+            let size = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+            if size > warn_size {
+                tracing::warn!(
+                    "{} is {size} bytes and is reparsed on every request, it may be slow to serve",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    if args.wrap_scalars {
+        for file in files {
+            let path = data_file_path(data_dir, file);
+            if read_data_file(&path, options)
+                .is_ok_and(|value| !value.is_array() && !value.is_object())
+            {
+                tracing::info!(
+                    "{} holds a scalar, serving it wrapped in an array",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    if args.strict || args.check {
+        let mut failed = false;
+        for file in files {
+            let path = data_file_path(data_dir, file);
+            match read_data_file(&path, options) {
+                Ok(value) => {
+                    for warning in strict_warnings(&value) {
+                        tracing::warn!("{}: {warning}", path.display());
+                    }
+                    if args.check {
+                        println!("{}: ok", path.display());
+                    }
+                }
+                Err(DataFileError::Read(e)) => {
+                    failed = true;
+                    println!("{}: {e}", path.display());
+                }
+                Err(DataFileError::Parse { error, .. }) => {
+                    failed = true;
+                    println!("{}: {error}", path.display());
+                }
+            }
+        }
+        if failed {
+            exit(1);
+        }
+    }
+
+    if args.fail_on_duplicate_id {
+        let mut found = false;
+        for file in files {
+            let duplicates = find_duplicate_ids(data_dir, file, &args.id_field, options);
+            if !duplicates.is_empty() {
+                found = true;
+                println!(
+                    "{} contains duplicate ids: {}",
+                    data_file_path(data_dir, file).display(),
+                    duplicates.join(", ")
+                );
+            }
+        }
+        if found {
+            exit(1);
+        }
+    }
+
+    if args.case_insensitive_routes {
+        let mut seen: HashMap<String, &String> = HashMap::new();
+        for file in files {
+            if let Some(other) = seen.insert(file.to_lowercase(), file) {
+                tracing::warn!("ambiguous case-insensitive endpoint: {other} and {file}");
+            }
+        }
+    }
+}
+
+// the paths given on the command line have to be routable and can't
+// clash with each other or with the routes json-server-rs always serves
+fn check_route_paths(args: &Args) {
+    let paths = [
+        ("--health-path", &args.health_path),
+        ("--index-endpoint", &args.index_endpoint),
+    ];
+    for (flag, path) in paths {
+        if !path.starts_with('/') || path.contains([':', '*']) {
+            println!("invalid {flag}, expected a path starting with / and without : or *: {path}");
+            exit(1);
+        }
+    }
 
+    // compared without a trailing slash, the listing answers either way
+    let index = args.index_endpoint.trim_end_matches('/');
+    let health = args.health_path.trim_end_matches('/');
+    let taken = |path: &str| ["/_config", "/_shutdown", "/_export", "/_echo"].contains(&path);
+    if taken(index) {
+        println!(
+            "invalid --index-endpoint, {} is already served",
+            args.index_endpoint
+        );
+        exit(1);
+    }
+    // `/` is the html page unless --no-root, or the listing with --index-endpoint /
+    if taken(health) || health == index || (health.is_empty() && !args.no_root) {
+        println!(
+            "invalid --health-path, {} is already served",
+            args.health_path
+        );
+        exit(1);
+    }
+}
+
+async fn serve(args: &Args, shared_state: Arc<AppState>) {
     // build our application with a route
-    let app = Router::new()
-        .route("/", get(root))
-        .route("/_health_check", get(health_check))
-        .route("/api", get(get_apis))
-        .route("/api/", get(get_apis))
+    let mut api = Router::new()
         .route("/api/:file", get(get_serve_json))
-        .layer(TraceLayer::new_for_http())
-        .layer(CompressionLayer::new())
-        .with_state(shared_state);
+        .route("/api/:file/_example", get(get_example))
+        .route("/api/:file/:endpoint", get(get_versioned_json))
+        .route("/api/:file/search", post(search_json))
+        .route("/api/:file/:endpoint/_example", get(get_versioned_example))
+        .route("/api/:file/:endpoint/search", post(search_versioned_json))
+        .route("/_export", get(export))
+        .route(
+            "/_echo",
+            post(echo).layer(DefaultBodyLimit::max(ECHO_BODY_LIMIT)),
+        );
+    // the listing answers with and without a trailing slash
+    let index = args.index_endpoint.trim_end_matches('/');
+    if index.is_empty() {
+        api = api.route("/", get(get_apis));
+    } else {
+        api = api
+            .route(index, get(get_apis))
+            .route(&format!("{index}/"), get(get_apis));
+        if !args.no_root {
+            api = api.route("/", get(root));
+        }
+    }
+
+    // ops endpoints, moved to their own listener when --admin-port is set
+    let mut ops = Router::new().route(&args.health_path, get(health_check));
+    if args.expose_config {
+        ops = ops.route("/_config", get(config));
+    }
+    if args.allow_remote_shutdown {
+        ops = ops.route("/_shutdown", post(remote_shutdown));
+    }
 
-    // add a fallback service for handling routes to unknown paths
-    let app = app.fallback(handler_404);
+    tokio::spawn(shutdown_on_signal(shared_state.clone()));
+    let shutdown = shared_state.shutdown.subscribe();
 
     // run it
     let addr = SocketAddr::from(([127, 0, 0, 1], args.port));
     println!("listening on http://{addr}");
     tracing::debug!("listening on http://{}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+
+    if let Some(admin_port) = args.admin_port {
+        let admin_addr = SocketAddr::from(([127, 0, 0, 1], admin_port));
+        println!("admin listening on http://{admin_addr}");
+        tracing::debug!("admin listening on http://{}", admin_addr);
+
+        let app = build_app(api, shared_state.clone());
+        let admin = build_app(ops, shared_state);
+        let server = bind(args, &addr)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(shutdown_requested(shutdown.clone()));
+        let admin_server = bind(args, &admin_addr)
+            .serve(admin.into_make_service())
+            .with_graceful_shutdown(shutdown_requested(shutdown.clone()));
+        spawn_on_start(args);
+        let (api_result, admin_result) = tokio::join!(server, admin_server);
+        api_result.unwrap();
+        admin_result.unwrap();
+    } else {
+        let app = build_app(api.merge(ops), shared_state);
+        let server = bind(args, &addr)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(shutdown_requested(shutdown.clone()));
+        spawn_on_start(args);
+        server.await.unwrap();
+    }
 }
 
-// basic handler that responds with a static string
-async fn root() -> Html<&'static str> {
-    Html("<h1>Hello, World!</h1>")
+// bind a listener, with the idle timeout from --keep-alive if set
+fn bind(args: &Args, addr: &SocketAddr) -> hyper::server::Builder<IdleTimeoutIncoming> {
+    let incoming = AddrIncoming::bind(addr).unwrap_or_else(|e| {
+        println!("can't listen on {addr}: {e}");
+        exit(1);
+    });
+    let incoming = IdleTimeoutIncoming {
+        incoming,
+        timeout: args
+            .keep_alive
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+    };
+    let builder = axum::Server::builder(incoming);
+    if args.keep_alive == Some(0) {
+        builder.http1_keepalive(false)
+    } else {
+        builder
+    }
 }
 
-async fn health_check() -> impl IntoResponse {
-    (StatusCode::OK, "ok")
+struct IdleTimeoutIncoming {
+    incoming: AddrIncoming,
+    timeout: Option<Duration>,
 }
 
-async fn handler_404() -> impl IntoResponse {
-    (StatusCode::NOT_FOUND, "nothing to see here")
+impl Accept for IdleTimeoutIncoming {
+    type Conn = IdleTimeoutStream;
+    type Error = std::io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let timeout = self.timeout;
+        Pin::new(&mut self.incoming)
+            .poll_accept(cx)
+            .map_ok(|stream| IdleTimeoutStream {
+                stream,
+                timeout,
+                idle: timeout.map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+            })
+    }
 }
 
-async fn get_apis(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    if state.files.is_empty() {
-        (
-            StatusCode::NOT_FOUND,
-            axum::Json(json!({"error": "not found"})),
-        )
-    } else {
-        (StatusCode::OK, axum::Json(json!(state.files)))
+// a connection that reads as closed once it has been idle for the timeout,
+// the timer only runs between a response going out and the next request
+struct IdleTimeoutStream {
+    stream: AddrStream,
+    timeout: Option<Duration>,
+    idle: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl AsyncRead for IdleTimeoutStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        match Pin::new(&mut self.stream).poll_read(cx, buf) {
+            // leaving the buffer empty tells hyper the client went away
+            Poll::Pending => match self.idle.as_mut().map(|idle| idle.as_mut().poll(cx)) {
+                Some(Poll::Ready(())) => Poll::Ready(Ok(())),
+                _ => Poll::Pending,
+            },
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    // a request is coming in, no timeout until it's answered
+                    self.idle = None;
+                }
+                Poll::Ready(result)
+            }
+        }
     }
 }
 
-#[derive(Deserialize)]
-struct JsonPathParams {
-    file: String,
+impl AsyncWrite for IdleTimeoutStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let written = Pin::new(&mut self.stream).poll_write(cx, buf);
+        if written.is_ready() {
+            if let Some(timeout) = self.timeout {
+                self.idle = Some(Box::pin(tokio::time::sleep(timeout)));
+                // hyper may already be parked on a read, have it poll again
+                // so the timer gets registered
+                cx.waker().wake_by_ref();
+            }
+        }
+        written
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
 }
 
-async fn get_serve_json(
-    State(state): State<Arc<AppState>>,
-    Path(JsonPathParams { file }): Path<JsonPathParams>,
-) -> impl IntoResponse {
-    // check if the file from the endpoint is in the vector of state.files
-    // so we can return a 404 if the file is not found
-    if !state.files.contains(&file) {
-        return (
-            StatusCode::NOT_FOUND,
-            axum::Json(json!({"error": "file not found"})),
-        );
+// run the --on-start command once the listeners are bound,
+// its output is logged and a failure doesn't stop the server
+fn spawn_on_start(args: &Args) {
+    let Some(command) = args.on_start.clone() else {
+        return;
+    };
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let output = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .output()
+            .await;
+        #[cfg(not(unix))]
+        let output = tokio::process::Command::new("cmd")
+            .arg("/C")
+            .arg(&command)
+            .output()
+            .await;
+
+        match output {
+            Ok(output) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if !stdout.trim().is_empty() {
+                    tracing::info!("on-start stdout: {}", stdout.trim_end());
+                }
+                if !stderr.trim().is_empty() {
+                    tracing::info!("on-start stderr: {}", stderr.trim_end());
+                }
+                if !output.status.success() {
+                    tracing::warn!("on-start command exited with {}", output.status);
+                }
+            }
+            Err(e) => tracing::warn!("unable to run on-start command: {e}"),
+        }
+    });
+}
+
+// resolves once shutdown was requested by a signal or POST /_shutdown
+async fn shutdown_requested(mut shutdown: watch::Receiver<bool>) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
     }
+}
 
-    let path = format!("{}/{}.json", state.data_dir, file);
+// turn ctrl-c and SIGTERM into a graceful shutdown
+async fn shutdown_on_signal(state: Arc<AppState>) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        signal(SignalKind::terminate())
+            .expect("Can't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    tracing::debug!("path: {}", path);
-    let str = fs::read_to_string(path).expect("Unable to read file");
-    match serde_json::from_str::<Value>(&str) {
-        Ok(v) => (StatusCode::OK, Json(v)),
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({"error": e.to_string()})),
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        () = terminate => {}
+    }
+    tracing::info!("shutting down");
+    state.shutdown.send_replace(true);
+}
+
+// add the shared middleware and state to a set of routes
+fn build_app(routes: Router<Arc<AppState>>, state: Arc<AppState>) -> Router {
+    let routes = routes
+        // add a fallback service for handling routes to unknown paths,
+        // set before the layers so they apply to it as well
+        .fallback(handler_404)
+        .layer(middleware::map_response_with_state(
+            state.clone(),
+            wrap_response,
+        ))
+        .with_state(state.clone());
+
+    // mocks are checked before routing, so a method the data routes don't
+    // allow can be mocked too, and outside the envelope and the OPTIONS
+    // fallback so they're sent as written
+    let mut app = Router::new()
+        .fallback_service(routes)
+        .layer(middleware::from_fn(answer_options))
+        .layer(middleware::from_fn_with_state(state.clone(), serve_mock));
+    for (name, value) in &state.response_headers {
+        app = app.layer(SetResponseHeaderLayer::overriding(
+            name.clone(),
+            value.clone(),
+        ));
+    }
+    app.layer(TraceLayer::new_for_http())
+        .layer(
+            CompressionLayer::new().compress_when(
+                DefaultPredicate::new().and(SizeAbove::new(state.min_compress_size)),
+            ),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), throttle))
+        .layer(middleware::from_fn_with_state(state.clone(), delay_errors))
+        .layer(middleware::from_fn_with_state(state.clone(), access_log))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            log_duplicate_requests,
+        ))
+        .with_state(state)
+}
+
+// stand-ins for the placeholders while the template is held as a parsed Value
+const DATA_PLACEHOLDER: &str = "\u{0}data";
+const ERROR_PLACEHOLDER: &str = "\u{0}error";
+
+// reject `{name}` placeholders that aren't in the allowed list
+fn check_placeholders(template: &str, allowed: &[&str]) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rest = &rest[start + 1..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if len > 0 && rest[len..].starts_with('}') && !allowed.contains(&&rest[..len]) {
+            return Err(format!("unknown placeholder {{{}}}", &rest[..len]));
+        }
+    }
+    Ok(())
+}
+
+// validate a --response-template and parse it with the placeholders swapped out
+fn parse_response_template(template: &str) -> Result<Value, String> {
+    check_placeholders(template, &["data", "error"])?;
+
+    let template = template
+        .replace("{data}", &json!(DATA_PLACEHOLDER).to_string())
+        .replace("{error}", &json!(ERROR_PLACEHOLDER).to_string());
+    serde_json::from_str(&template).map_err(|e| format!("not valid json: {e}"))
+}
+
+// put the response data and error into a copy of the template
+fn fill_template(template: &Value, data: &Value, error: &Value) -> Value {
+    match template {
+        Value::String(s) if s == DATA_PLACEHOLDER => data.clone(),
+        Value::String(s) if s == ERROR_PLACEHOLDER => error.clone(),
+        Value::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| fill_template(item, data, error))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), fill_template(v, data, error)))
+                .collect(),
         ),
+        other => other.clone(),
     }
 }
 
-// create a function that finds all .json files in the directory data
-// and returns a vector of the file names
-fn get_json_files(data_dir: String) -> Result<Vec<String>, Error> {
-    let mut json_files = Vec::new();
-    for entry in fs::read_dir(data_dir).expect("read_dir call failed") {
-        let entry = entry.unwrap();
-        let path = entry.path();
-        if path.extension().unwrap() == "json" {
-            // trim of the .json extension
-            let file_name = path.file_name().unwrap().to_str().unwrap().to_string();
-            let file_name = file_name.replace(".json", "");
-            json_files.push(file_name);
+// wrap json bodies in the --response-template envelope, if one is configured
+async fn wrap_response(State(state): State<Arc<AppState>>, response: Response) -> Response {
+    let Some(template) = &state.response_template else {
+        return response;
+    };
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type.as_bytes().starts_with(b"application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = hyper::body::to_bytes(body).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let Ok(value) = serde_json::from_slice::<Value>(&bytes) else {
+        return (parts, bytes).into_response();
+    };
+
+    let wrapped = if parts.status.is_client_error() || parts.status.is_server_error() {
+        let error = value.get("error").cloned().unwrap_or(value);
+        fill_template(template, &Value::Null, &error)
+    } else {
+        fill_template(template, &value, &Value::Null)
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    (parts, Json(wrapped)).into_response()
+}
+
+// fields that can be used in --access-log-format
+const ACCESS_LOG_FIELDS: [&str; 5] = ["method", "path", "query", "status", "latency_ms"];
+
+// write the --access-log-format line for each request, if one is configured
+async fn access_log(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let Some(format) = &state.access_log_format else {
+        return next.run(request).await;
+    };
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().unwrap_or("").to_string();
+    let started_at = Instant::now();
+
+    let response = next.run(request).await;
+
+    let line = format
+        .replace("{method}", &method)
+        .replace("{path}", &path)
+        .replace("{query}", &query)
+        .replace("{status}", response.status().as_str())
+        .replace(
+            "{latency_ms}",
+            &started_at.elapsed().as_millis().to_string(),
+        );
+    tracing::info!("{line}");
+    response
+}
+
+#[derive(Deserialize)]
+struct MockFile {
+    #[serde(default = "default_mock_status")]
+    status: u16,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Sent as json, no body when left out
+    body: Option<Value>,
+}
+
+fn default_mock_status() -> u16 {
+    200
+}
+
+struct Mock {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Option<Value>,
+}
+
+// read the --mock file, any invalid entry stops the server from starting
+fn load_mocks(path: &str) -> HashMap<String, Mock> {
+    let mocks = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|str| {
+            serde_json::from_str::<HashMap<String, MockFile>>(&str).map_err(|e| e.to_string())
+        })
+        .unwrap_or_else(|e| {
+            println!("invalid --mock {path}: {e}");
+            exit(1);
+        });
+
+    let mut parsed = HashMap::new();
+    for (route, mock) in mocks {
+        let (Some((method, mock_path)), Ok(status)) =
+            (route.split_once(' '), StatusCode::from_u16(mock.status))
+        else {
+            println!("invalid --mock entry, expected \"METHOD /path\" and a valid status: {route}");
+            exit(1);
+        };
+        let Ok(method) = Method::from_bytes(method.to_uppercase().as_bytes()) else {
+            println!("invalid --mock method: {route}");
+            exit(1);
+        };
+        if !mock_path.starts_with('/') {
+            println!("invalid --mock path, expected it to start with /: {route}");
+            exit(1);
         }
+        let headers = HeaderMap::try_from(&mock.headers).unwrap_or_else(|e| {
+            println!("invalid --mock headers for {route}: {e}");
+            exit(1);
+        });
+        parsed.insert(
+            format!("{method} {mock_path}"),
+            Mock {
+                status,
+                headers,
+                body: mock.body,
+            },
+        );
+    }
+    parsed
+}
+
+// answer with the --mock response for this method and path if there is one
+async fn serve_mock(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let route = format!("{} {}", request.method(), request.uri().path());
+    let Some(mock) = state.mocks.get(&route) else {
+        return next.run(request).await;
+    };
+    let mut response = match &mock.body {
+        Some(body) => Json(body).into_response(),
+        None => ().into_response(),
+    };
+    *response.status_mut() = mock.status;
+    response.headers_mut().extend(mock.headers.clone());
+    response
+}
+
+// OPTIONS probes on paths or routes without their own OPTIONS handler
+// get an empty 204 rather than a 404 or 405; on a route the Allow header
+// axum puts on its 405 lists the methods the route registers
+async fn answer_options(request: Request<Body>, next: Next<Body>) -> Response {
+    if request.method() != Method::OPTIONS {
+        return next.run(request).await;
+    }
+    let response = next.run(request).await;
+    match response.status() {
+        StatusCode::NOT_FOUND => StatusCode::NO_CONTENT.into_response(),
+        StatusCode::METHOD_NOT_ALLOWED => {
+            let mut methods = response
+                .headers()
+                .get(header::ALLOW)
+                .and_then(|allow| allow.to_str().ok())
+                .map(str::to_string)
+                .unwrap_or_default();
+            if !methods.is_empty() {
+                methods.push(',');
+            }
+            methods.push_str("OPTIONS");
+            (StatusCode::NO_CONTENT, [(header::ALLOW, methods)]).into_response()
+        }
+        _ => response,
+    }
+}
+
+// hold back failing /api responses for --delay-on-error
+async fn delay_errors(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let path = request.uri().path();
+    let Some(delay) = state
+        .delay_on_error
+        .filter(|_| path == "/api" || path.starts_with("/api/"))
+    else {
+        return next.run(request).await;
+    };
+
+    let response = next.run(request).await;
+    if response.status().is_client_error() || response.status().is_server_error() {
+        tokio::time::sleep(delay).await;
+    }
+    response
+}
+
+// bodies known to be smaller than this are sent at full speed under --throttle
+const THROTTLE_MIN_BYTES: u64 = 1024;
+// how often a throttled body sends its next slice
+const THROTTLE_TICK: Duration = Duration::from_millis(100);
+
+// pace /api response bodies to --throttle bytes per second, this runs
+// outside the compression layer so it's the compressed bytes that are paced
+async fn throttle(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let path = request.uri().path();
+    let Some(rate) = state
+        .throttle
+        .filter(|_| path == "/api" || path.starts_with("/api/"))
+    else {
+        return next.run(request).await;
+    };
+
+    let response = next.run(request).await;
+    // compressed bodies don't report their size, uncompressed ones still
+    // carry the content-length they were given
+    let len = response
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+        .or_else(|| response.body().size_hint().exact());
+    if len.is_some_and(|len| len < THROTTLE_MIN_BYTES) {
+        return response;
+    }
+
+    let (parts, mut body) = response.into_parts();
+    let (mut sender, throttled) = Body::channel();
+    tokio::spawn(async move {
+        let slice_len = usize::try_from(rate / 10).unwrap_or(usize::MAX).max(1);
+        let mut ticks = tokio::time::interval(THROTTLE_TICK);
+        while let Some(data) = body.data().await {
+            let Ok(data) = data else {
+                sender.abort();
+                return;
+            };
+            let mut start = 0;
+            while start < data.len() {
+                let end = data.len().min(start + slice_len);
+                ticks.tick().await;
+                // the client went away, stop sending
+                if sender.send_data(data.slice(start..end)).await.is_err() {
+                    return;
+                }
+                start = end;
+            }
+        }
+    });
+    Response::from_parts(parts, axum::body::boxed(throttled))
+}
+
+// how long and how many request ids are remembered to spot retries
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(10);
+const DUPLICATE_CAPACITY: usize = 1024;
+
+// warn when a client sends the same X-Request-Id or Idempotency-Key again
+// shortly after, which points at retry loops; the request is served as usual
+async fn log_duplicate_requests(
+    State(state): State<Arc<AppState>>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let id = ["x-request-id", "idempotency-key"]
+        .iter()
+        .find_map(|name| request.headers().get(*name)?.to_str().ok());
+    if let Some(id) = id {
+        let now = Instant::now();
+        let mut recent = state.recent_request_ids.lock().unwrap();
+        while recent
+            .front()
+            .is_some_and(|(_, seen_at)| now.duration_since(*seen_at) > DUPLICATE_WINDOW)
+        {
+            recent.pop_front();
+        }
+        if let Some((_, seen_at)) = recent.iter().rev().find(|(seen, _)| seen == id) {
+            tracing::warn!(
+                "duplicate request id {id} on {} {}, last seen {}ms ago",
+                request.method(),
+                request.uri().path(),
+                now.duration_since(*seen_at).as_millis()
+            );
+        }
+        if recent.len() == DUPLICATE_CAPACITY {
+            recent.pop_front();
+        }
+        recent.push_back((id.to_string(), now));
+    }
+    next.run(request).await
+}
+
+// basic handler that responds with a static string
+async fn root() -> Html<&'static str> {
+    Html("<h1>Hello, World!</h1>")
+}
+
+#[derive(Deserialize)]
+struct HealthParams {
+    format: Option<String>,
+}
+
+async fn health_check(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HealthParams>,
+) -> impl IntoResponse {
+    if params.format.as_deref() == Some("json") {
+        return (
+            StatusCode::OK,
+            Json(json!({
+                "status": "ok",
+                "uptime_secs": state.started_at.elapsed().as_secs(),
+                "version": env!("CARGO_PKG_VERSION"),
+                "endpoints": state.files.read().unwrap().len(),
+            })),
+        )
+            .into_response();
+    }
+    (StatusCode::OK, "ok").into_response()
+}
+
+// largest request body /_echo accepts, bigger bodies get a 413
+const ECHO_BODY_LIMIT: usize = 64 * 1024;
+
+// send the request back as json so clients can see what they actually sent
+async fn echo(method: Method, headers: HeaderMap, body: Bytes) -> impl IntoResponse {
+    let headers: serde_json::Map<String, Value> = headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                json!(String::from_utf8_lossy(value.as_bytes())),
+            )
+        })
+        .collect();
+    // echo json bodies as json, anything else as a string
+    let body = serde_json::from_slice::<Value>(&body)
+        .unwrap_or_else(|_| json!(String::from_utf8_lossy(&body)));
+
+    Json(json!({
+        "method": method.as_str(),
+        "headers": headers,
+        "body": body,
+    }))
+}
+
+// the parsed flags along with what they don't show directly:
+// the address served and the cargo features the binary was built with
+fn effective_config(args: &Args) -> Value {
+    let mut features = Vec::new();
+    if cfg!(feature = "transform") {
+        features.push("transform");
+    }
+    if cfg!(feature = "embedded") {
+        features.push("embedded");
+    }
+    if cfg!(feature = "protobuf") {
+        features.push("protobuf");
+    }
+    json!({
+        "args": args,
+        "features": features,
+        "listen": SocketAddr::from(([127, 0, 0, 1], args.port)),
+    })
+}
+
+async fn config(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.config.clone())
+}
+
+async fn remote_shutdown(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    tracing::info!("shutdown requested through /_shutdown");
+    state.shutdown.send_replace(true);
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({"status": "shutting down"})),
+    )
+}
+
+async fn handler_404() -> impl IntoResponse {
+    (StatusCode::NOT_FOUND, "nothing to see here")
+}
+
+#[derive(Deserialize)]
+struct ApisParams {
+    /// List `{"name","count"}` objects instead of bare names
+    #[serde(rename = "withCounts", default)]
+    with_counts: bool,
+}
+
+async fn get_apis(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ApisParams>,
+) -> impl IntoResponse {
+    // versioned endpoints are listed once by their version, from a copy so a
+    // SIGHUP rescan isn't held up while files are read for their counts
+    let mut listing: Vec<&str> = Vec::new();
+    let files = state.files.read().unwrap().clone();
+    for file in &files {
+        let name = file
+            .split_once('/')
+            .map_or(file.as_str(), |(version, _)| version);
+        if !listing.contains(&name) {
+            listing.push(name);
+        }
+    }
+
+    if listing.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            axum::Json(json!({"error": "not found"})),
+        );
+    }
+    if !params.with_counts {
+        return (StatusCode::OK, axum::Json(json!(listing)));
+    }
+
+    // only array collections have a record count, versions and
+    // object or unreadable files are listed with a null one
+    let counted: Vec<Value> = listing
+        .iter()
+        .map(|name| {
+            let count = files
+                .iter()
+                .any(|file| file == name)
+                .then(|| read_endpoint(&state, name, name).ok())
+                .flatten()
+                .and_then(|value| value.as_array().map(Vec::len));
+            json!({"name": name, "count": count})
+        })
+        .collect();
+    (StatusCode::OK, axum::Json(json!(counted)))
+}
+
+// endpoints served under a version, e.g. `users` for `v1/users`
+fn version_endpoints(state: &AppState, version: &str) -> Vec<String> {
+    state
+        .files
+        .read()
+        .unwrap()
+        .iter()
+        .filter_map(|file| file.strip_prefix(version)?.strip_prefix('/'))
+        .map(str::to_string)
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct JsonPathParams {
+    file: String,
+}
+
+#[derive(Deserialize)]
+struct JsonQueryParams {
+    /// Return the sorted unique values of this field instead of the records
+    #[serde(rename = "_distinct")]
+    distinct: Option<String>,
+    /// Response format overriding the Accept header, e.g. `csv`
+    #[serde(rename = "_format")]
+    format: Option<String>,
+    /// Comma separated fields to leave out of each record, e.g. `password,meta.token`
+    #[serde(rename = "_omit")]
+    omit: Option<String>,
+    /// Serve the `users.<variant>` file instead if there is one
+    #[serde(rename = "_variant")]
+    variant: Option<String>,
+    /// Fail with this 4xx/5xx status instead, overriding --force-status
+    #[serde(rename = "_status")]
+    status: Option<String>,
+}
+
+async fn get_serve_json(
+    State(state): State<Arc<AppState>>,
+    Path(JsonPathParams { file }): Path<JsonPathParams>,
+    Query(params): Query<JsonQueryParams>,
+    headers: HeaderMap,
+) -> Response {
+    // `/api/v1` on its own lists that version's endpoints
+    let endpoints = version_endpoints(&state, &file);
+    if !endpoints.is_empty() {
+        return (StatusCode::OK, Json(json!(endpoints))).into_response();
+    }
+    serve_endpoint(&state, &file, params, &headers).await
+}
+
+#[derive(Deserialize)]
+struct VersionedPathParams {
+    /// Named `file` to share the segment with the other `/api/:file/...` routes
+    #[serde(rename = "file")]
+    version: String,
+    endpoint: String,
+}
+
+async fn get_versioned_json(
+    State(state): State<Arc<AppState>>,
+    Path(VersionedPathParams { version, endpoint }): Path<VersionedPathParams>,
+    Query(params): Query<JsonQueryParams>,
+    headers: HeaderMap,
+) -> Response {
+    serve_endpoint(&state, &format!("{version}/{endpoint}"), params, &headers).await
+}
+
+// render an endpoint in the format the request asked for
+async fn serve_endpoint(
+    state: &AppState,
+    file: &str,
+    params: JsonQueryParams,
+    headers: &HeaderMap,
+) -> Response {
+    let (file, mut value) = match load_endpoint(state, file, params.variant.as_deref()).await {
+        Ok(loaded) => loaded,
+        Err(response) => return response.into_response(),
+    };
+    let file = file.as_str();
+
+    let forced = match params.status.as_deref() {
+        Some(status) => match error_status(status) {
+            Some(status) => Some(status),
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({"error": "_status must be a 4xx or 5xx status code"})),
+                )
+                    .into_response()
+            }
+        },
+        None => state.forced_statuses.get(file).copied(),
+    };
+    if let Some(status) = forced {
+        let reason = status.canonical_reason().unwrap_or("forced error");
+        return (status, Json(json!({"error": reason, "forced": true}))).into_response();
+    }
+
+    for field in params.omit.iter().flat_map(|omit| omit.split(',')) {
+        let path: Vec<&str> = field.split('.').collect();
+        omit_field(&mut value, &path);
+    }
+
+    let value = match (&params.distinct, value) {
+        (Some(field), Value::Array(records)) => distinct_values(&records, field),
+        (_, value) => value,
+    };
+
+    let mut value = match transform_response(state, value).await {
+        Ok(value) => value,
+        Err(response) => return response.into_response(),
+    };
+    if state.sort_keys {
+        value.sort_all_objects();
+    }
+
+    if params.format.as_deref() == Some("csv") || accepts(headers, "text/csv") {
+        let Value::Array(records) = value else {
+            return (
+                StatusCode::NOT_ACCEPTABLE,
+                Json(json!({"error": "only array collections can be served as csv"})),
+            )
+                .into_response();
+        };
+        let csv = records_to_csv(&records);
+        if exceeds_max_response(state, csv.len()) {
+            return response_too_large();
+        }
+        return ([(header::CONTENT_TYPE, "text/csv")], csv).into_response();
+    }
+
+    if params.format.as_deref() == Some("ndjson") {
+        let Value::Array(records) = value else {
+            return (
+                StatusCode::NOT_ACCEPTABLE,
+                Json(json!({"error": "only array collections can be served as ndjson"})),
+            )
+                .into_response();
+        };
+        return (
+            [(header::CONTENT_TYPE, "application/x-ndjson")],
+            axum::body::boxed(stream_ndjson(records)),
+        )
+            .into_response();
+    }
+
+    #[cfg(feature = "protobuf")]
+    if accepts(headers, "application/x-protobuf") {
+        return protobuf_response(state, file, value);
+    }
+
+    if accepts(headers, "application/yaml") {
+        return match serde_yaml::to_string(&json_to_yaml(value)) {
+            Ok(yaml) if exceeds_max_response(state, yaml.len()) => response_too_large(),
+            Ok(yaml) => ([(header::CONTENT_TYPE, "application/yaml")], yaml).into_response(),
+            Err(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": e.to_string()})),
+            )
+                .into_response(),
+        };
+    }
+    // `_distinct` projects values out of the records, there is nothing to wrap
+    if state.jsonapi && params.distinct.is_none() {
+        if let Value::Array(records) = value {
+            // the resource type is the endpoint name without its version
+            let kind = file.rsplit('/').next().unwrap_or(file);
+            let mut response = json_response(state, &to_jsonapi(records, kind, &state.id_field));
+            if response.status().is_success() {
+                response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/vnd.api+json"),
+                );
+            }
+            return response;
+        }
+    }
+    json_response(state, &value)
+}
+
+// wrap records as JSON:API resources, the id field moves out of the
+// attributes and becomes a string, records that aren't objects are kept as is
+fn to_jsonapi(records: Vec<Value>, kind: &str, id_field: &str) -> Value {
+    let data: Vec<Value> = records
+        .into_iter()
+        .map(|record| {
+            let Value::Object(mut attributes) = record else {
+                return record;
+            };
+            let mut resource = json!({"type": kind});
+            match attributes.shift_remove(id_field) {
+                Some(Value::String(id)) => resource["id"] = Value::String(id),
+                Some(id) => resource["id"] = Value::String(id.to_string()),
+                None => {}
+            }
+            resource["attributes"] = Value::Object(attributes);
+            resource
+        })
+        .collect();
+    json!({ "data": data })
+}
+
+// stream one json document per line, serializing each record as it is sent
+fn stream_ndjson(records: Vec<Value>) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        for record in records {
+            let mut line = record.to_string();
+            line.push('\n');
+            // the client went away, stop serializing
+            if sender.send_data(Bytes::from(line)).await.is_err() {
+                return;
+            }
+        }
+    });
+    body
+}
+
+// one row per record with the top-level fields as columns,
+// in the order they first appear; nested values are written as json
+fn records_to_csv(records: &[Value]) -> String {
+    let mut columns: Vec<&String> = Vec::new();
+    for record in records.iter().filter_map(Value::as_object) {
+        for key in record.keys() {
+            if !columns.contains(&key) {
+                columns.push(key);
+            }
+        }
+    }
+
+    let mut csv = String::new();
+    let header: Vec<String> = columns.iter().map(|column| csv_cell(column)).collect();
+    csv.push_str(&header.join(","));
+    csv.push_str("\r\n");
+    for record in records {
+        let row: Vec<String> = columns
+            .iter()
+            .map(|column| match record.get(column.as_str()) {
+                None | Some(Value::Null) => String::new(),
+                Some(Value::String(s)) => csv_cell(s),
+                Some(value) => csv_cell(&value.to_string()),
+            })
+            .collect();
+        csv.push_str(&row.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// quote a cell when it contains a separator, quote or line break
+fn csv_cell(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// whether the Accept header lists the given media type
+fn accepts(headers: &HeaderMap, media_type: &str) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|accepted| accepted.split(';').next().unwrap_or("").trim() == media_type)
+}
+
+// numbers are converted by hand, with arbitrary_precision serde_json::Number
+// only serializes as a plain number into serde_json itself
+fn json_to_yaml(value: Value) -> serde_yaml::Value {
+    match value {
+        Value::Null => serde_yaml::Value::Null,
+        Value::Bool(b) => serde_yaml::Value::Bool(b),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                serde_yaml::Value::from(i)
+            } else if let Some(u) = n.as_u64() {
+                serde_yaml::Value::from(u)
+            } else {
+                serde_yaml::Value::from(n.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        Value::String(s) => serde_yaml::Value::String(s),
+        Value::Array(items) => {
+            serde_yaml::Value::Sequence(items.into_iter().map(json_to_yaml).collect())
+        }
+        Value::Object(map) => serde_yaml::Value::Mapping(
+            map.into_iter()
+                .map(|(k, v)| (serde_yaml::Value::String(k), json_to_yaml(v)))
+                .collect(),
+        ),
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchBody {
+    /// Field values a record must equal to match
+    #[serde(rename = "where", default)]
+    filter: serde_json::Map<String, Value>,
+    /// Fields a record must have, whatever their value
+    #[serde(default)]
+    has: Vec<String>,
+    /// Fields a record must not have at all
+    #[serde(default)]
+    missing: Vec<String>,
+    sort: Option<String>,
+    /// `asc` (default) or `desc`
+    order: Option<String>,
+    /// Put records with a null or missing sort field `first` or `last` (default)
+    nulls: Option<String>,
+    /// Dot-separated fields to leave out of the matched records
+    #[serde(default)]
+    omit: Vec<String>,
+    /// Answer with the sorted unique values of this field among the matched records
+    distinct: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+async fn search_json(
+    State(state): State<Arc<AppState>>,
+    Path(JsonPathParams { file }): Path<JsonPathParams>,
+    Json(body): Json<SearchBody>,
+) -> Response {
+    search_endpoint(&state, &file, body).await
+}
+
+async fn search_versioned_json(
+    State(state): State<Arc<AppState>>,
+    Path(VersionedPathParams { version, endpoint }): Path<VersionedPathParams>,
+    Json(body): Json<SearchBody>,
+) -> Response {
+    search_endpoint(&state, &format!("{version}/{endpoint}"), body).await
+}
+
+async fn search_endpoint(state: &AppState, file: &str, body: SearchBody) -> Response {
+    let records = match load_endpoint(state, file, None).await {
+        Ok((_, Value::Array(records))) => records,
+        Ok(_) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({"error": "file is not a collection"})),
+            )
+                .into_response()
+        }
+        Err(response) => return response.into_response(),
+    };
+
+    let mut records: Vec<Value> = records
+        .into_iter()
+        .filter(|record| matches_filter(record, &body.filter))
+        .filter(|record| body.has.iter().all(|field| record.get(field).is_some()))
+        .filter(|record| body.missing.iter().all(|field| record.get(field).is_none()))
+        .collect();
+
+    if let Some(field) = &body.sort {
+        sort_records(
+            &mut records,
+            field,
+            body.order.as_deref() == Some("desc"),
+            body.nulls.as_deref() == Some("first"),
+        );
+    }
+
+    for field in &body.omit {
+        let path: Vec<&str> = field.split('.').collect();
+        for record in &mut records {
+            omit_field(record, &path);
+        }
+    }
+    // pages through the distinct values rather than the records
+    if let Some(field) = &body.distinct {
+        if let Value::Array(values) = distinct_values(&records, field) {
+            records = values;
+        }
+    }
+
+    let records = records
+        .into_iter()
+        .skip(body.offset.unwrap_or(0))
+        .take(body.limit.unwrap_or(usize::MAX))
+        .collect();
+    match transform_response(state, Value::Array(records)).await {
+        Ok(value) => json_response(state, &value),
+        Err(response) => response.into_response(),
+    }
+}
+
+#[cfg(feature = "transform")]
+fn load_transform(args: &Args) -> Option<Transform> {
+    args.transform.as_deref().map(|path| {
+        Transform::load(path).unwrap_or_else(|e| {
+            println!("invalid --transform: {e}");
+            exit(1);
+        })
+    })
+}
+
+#[cfg(not(feature = "transform"))]
+fn load_transform(args: &Args) {
+    if args.transform.is_some() {
+        println!("--transform needs json-server-rs built with the `transform` feature");
+        exit(1);
+    }
+}
+
+// fuel a --transform module gets per response, roughly one unit per wasm
+// instruction, so a module stuck in a loop traps instead of pinning a thread
+#[cfg(feature = "transform")]
+const TRANSFORM_FUEL: u64 = 1_000_000_000;
+
+// a compiled --transform module, instantiated afresh for every response
+#[cfg(feature = "transform")]
+#[derive(Clone)]
+struct Transform {
+    engine: wasmtime::Engine,
+    module: wasmtime::Module,
+}
+
+#[cfg(feature = "transform")]
+impl Transform {
+    fn load(path: &str) -> wasmtime::Result<Self> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config)?;
+        let module = wasmtime::Module::from_file(&engine, path)?;
+        Ok(Self { engine, module })
+    }
+
+    // JSON bytes in, JSON bytes out: the module exports `memory`,
+    // `alloc(len) -> ptr` and `transform(ptr, len) -> out_ptr << 32 | out_len`
+    fn apply(&self, input: &[u8]) -> wasmtime::Result<Vec<u8>> {
+        let mut store = wasmtime::Store::new(&self.engine, ());
+        store.set_fuel(TRANSFORM_FUEL)?;
+        let instance = wasmtime::Instance::new(&mut store, &self.module, &[])?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::format_err!("module doesn't export `memory`"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let transform = instance.get_typed_func::<(i32, i32), i64>(&mut store, "transform")?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, usize::try_from(ptr)?, input)?;
+
+        let packed = transform.call(&mut store, (ptr, len))?;
+        let out_ptr = usize::try_from(packed >> 32)?;
+        let mut output = vec![0; usize::try_from(packed & 0xffff_ffff)?];
+        memory.read(&store, out_ptr, &mut output)?;
+        Ok(output)
+    }
+}
+
+// run --transform over a response body, a failing module is a 500; the
+// module runs on a blocking thread so it can't stall the async workers
+#[cfg(feature = "transform")]
+async fn transform_response(
+    state: &AppState,
+    value: Value,
+) -> Result<Value, (StatusCode, Json<Value>)> {
+    let Some(transform) = state.transform.clone() else {
+        return Ok(value);
+    };
+    tokio::task::spawn_blocking(move || {
+        let output = transform.apply(&serde_json::to_vec(&value)?)?;
+        Ok(serde_json::from_slice(&output)?)
+    })
+    .await
+    .map_err(wasmtime::Error::from)
+    .and_then(|result| result)
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": format!("transform failed: {e:#}")})),
+        )
+    })
+}
+
+#[cfg(not(feature = "transform"))]
+async fn transform_response(
+    _state: &AppState,
+    value: Value,
+) -> Result<Value, (StatusCode, Json<Value>)> {
+    Ok(value)
+}
+
+// map endpoints to the message types of --proto-message
+#[cfg(feature = "protobuf")]
+fn load_proto_messages(args: &Args) -> HashMap<String, prost_reflect::MessageDescriptor> {
+    let Some(path) = &args.proto_descriptor else {
+        if !args.proto_message.is_empty() {
+            println!("--proto-message needs a --proto-descriptor");
+            exit(1);
+        }
+        return HashMap::new();
+    };
+    let pool = fs_err::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| {
+            prost_reflect::DescriptorPool::decode(bytes.as_slice()).map_err(|e| e.to_string())
+        })
+        .unwrap_or_else(|e| {
+            println!("invalid --proto-descriptor: {e}");
+            exit(1);
+        });
+
+    let mut messages = HashMap::new();
+    for rule in &args.proto_message {
+        let Some((file, name)) = rule.split_once('=') else {
+            println!("invalid --proto-message, expected <endpoint>=<message>: {rule}");
+            exit(1);
+        };
+        let Some(message) = pool.get_message_by_name(name) else {
+            println!("--proto-message: no message {name} in {path}");
+            exit(1);
+        };
+        messages.insert(file.to_string(), message);
+    }
+    messages
+}
+
+#[cfg(not(feature = "protobuf"))]
+fn load_proto_messages(args: &Args) {
+    if args.proto_descriptor.is_some() || !args.proto_message.is_empty() {
+        println!("--proto-descriptor needs json-server-rs built with the `protobuf` feature");
+        exit(1);
+    }
+}
+
+// encode each record as a length-delimited message, the way protobuf
+// streams several messages one after another
+#[cfg(feature = "protobuf")]
+fn protobuf_response(state: &AppState, file: &str, value: Value) -> Response {
+    use prost_reflect::prost::Message;
+
+    let Some(message) = state.proto_messages.get(file) else {
+        return (
+            StatusCode::NOT_ACCEPTABLE,
+            Json(json!({"error": "no --proto-message for this endpoint"})),
+        )
+            .into_response();
+    };
+    let Value::Array(records) = value else {
+        return (
+            StatusCode::NOT_ACCEPTABLE,
+            Json(json!({"error": "only array collections can be served as protobuf"})),
+        )
+            .into_response();
+    };
+
+    // fixtures usually carry more fields than the message declares
+    let options = prost_reflect::DeserializeOptions::new().deny_unknown_fields(false);
+    let mut body = Vec::new();
+    for (index, record) in records.into_iter().enumerate() {
+        match prost_reflect::DynamicMessage::deserialize_with_options(
+            message.clone(),
+            record,
+            &options,
+        ) {
+            Ok(encoded) => encoded
+                .encode_length_delimited(&mut body)
+                .expect("a Vec grows as needed"),
+            Err(e) => return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({
+                    "error": format!("record {index} doesn't match {}: {e}", message.full_name())
+                })),
+            )
+                .into_response(),
+        }
+    }
+    if exceeds_max_response(state, body.len()) {
+        return response_too_large();
+    }
+    ([(header::CONTENT_TYPE, "application/x-protobuf")], body).into_response()
+}
+
+// serialize a value as the json response, unless it exceeds --max-response
+fn json_response(state: &AppState, value: &Value) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(body) if exceeds_max_response(state, body.len()) => response_too_large(),
+        Ok(body) => ([(header::CONTENT_TYPE, "application/json")], body).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"error": e.to_string()})),
+        )
+            .into_response(),
+    }
+}
+
+fn exceeds_max_response(state: &AppState, len: usize) -> bool {
+    state
+        .max_response
+        .is_some_and(|max| u64::try_from(len).unwrap_or(u64::MAX) > max)
+}
+
+fn response_too_large() -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({
+            "error": "response too large",
+            "hint": "page through the collection with POST /api/:file/search and offset/limit",
+        })),
+    )
+        .into_response()
+}
+
+// stream every endpoint as one db.json-style object, endpoint name to contents
+async fn export(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let files = state.files.read().unwrap().clone();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut written = 0;
+        for file in &files {
+            // an error body would be reimported as data, leave the file out instead
+            let value = match read_endpoint(&state, file, file) {
+                Ok(value) => value,
+                Err((_, Json(error))) => {
+                    tracing::warn!("_export: skipping {file}: {error}");
+                    continue;
+                }
+            };
+            let separator = if written == 0 { "{" } else { "," };
+            let chunk = format!("{separator}{}:{value}", json!(file));
+            if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                return;
+            }
+            written += 1;
+        }
+        let end = if written == 0 { "{}" } else { "}" };
+        let _ = sender.send_data(Bytes::from(end)).await;
+    });
+    (
+        [(header::CONTENT_TYPE, "application/json")],
+        axum::body::boxed(body),
+    )
+}
+
+// how many records are sampled to infer the shape of a collection
+const EXAMPLE_SAMPLE_SIZE: usize = 5;
+
+async fn get_example(
+    State(state): State<Arc<AppState>>,
+    Path(JsonPathParams { file }): Path<JsonPathParams>,
+) -> impl IntoResponse {
+    example_endpoint(&state, &file).await
+}
+
+async fn get_versioned_example(
+    State(state): State<Arc<AppState>>,
+    Path(VersionedPathParams { version, endpoint }): Path<VersionedPathParams>,
+) -> impl IntoResponse {
+    example_endpoint(&state, &format!("{version}/{endpoint}")).await
+}
+
+async fn example_endpoint(
+    state: &AppState,
+    file: &str,
+) -> Result<(StatusCode, Json<Value>), (StatusCode, Json<Value>)> {
+    let example = match load_endpoint(state, file, None).await {
+        Ok((_, Value::Array(records))) => records
+            .iter()
+            .take(EXAMPLE_SAMPLE_SIZE)
+            .map(example_value)
+            .reduce(merge_examples)
+            .unwrap_or_else(|| json!({})),
+        Ok((_, value)) => example_value(&value),
+        Err(response) => return Err(response),
+    };
+    Ok((StatusCode::OK, Json(example)))
+}
+
+// keep the structure of a value but swap its contents for placeholders
+fn example_value(value: &Value) -> Value {
+    match value {
+        Value::Null => Value::Null,
+        Value::Bool(_) => json!(false),
+        Value::Number(_) => json!(0),
+        Value::String(_) => json!("string"),
+        Value::Array(items) => match items.first() {
+            Some(item) => json!([example_value(item)]),
+            None => json!([]),
+        },
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .map(|(k, v)| (k.clone(), example_value(v)))
+                .collect(),
+        ),
+    }
+}
+
+// combine two examples so fields seen in either show up,
+// a null placeholder gives way to a typed one
+fn merge_examples(a: Value, b: Value) -> Value {
+    match (a, b) {
+        (Value::Object(mut a), Value::Object(b)) => {
+            for (key, value) in b {
+                match a.get_mut(&key) {
+                    Some(existing) => *existing = merge_examples(existing.take(), value),
+                    None => {
+                        a.insert(key, value);
+                    }
+                }
+            }
+            Value::Object(a)
+        }
+        (Value::Null, b) => b,
+        (a, _) => a,
+    }
+}
+
+// look up an endpoint by name and read its data, or that of the requested
+// variant when the endpoint has one by that name; the name is returned as
+// the endpoint is called in the data_dir, which differs from the request
+// with --case-insensitive-routes
+async fn load_endpoint(
+    state: &AppState,
+    file: &str,
+    variant: Option<&str>,
+) -> Result<(String, Value), (StatusCode, Json<Value>)> {
+    // check if the file from the endpoint is in the vector of state.files
+    // so we can return a 404 if the file is not found, the variant is looked
+    // up under the same locks so both come from the same scan
+    let matched = {
+        let files = state.files.read().unwrap();
+        let matched = if state.case_insensitive_routes {
+            let file = file.to_lowercase();
+            files.iter().find(|f| f.to_lowercase() == file).cloned()
+        } else {
+            files.iter().find(|f| *f == file).cloned()
+        };
+        let variants = state.variants.read().unwrap();
+        matched
+            .map(|file| {
+                let source = variant
+                    .map(|variant| format!("{file}.{variant}"))
+                    .filter(|name| variants.contains(name));
+                (file, source)
+            })
+            .ok_or_else(|| suggest_endpoints(&files, file))
+    };
+    let (file, source) = match matched {
+        Ok(matched) => matched,
+        Err(suggestions) if suggestions.is_empty() => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                axum::Json(json!({"error": "file not found"})),
+            ))
+        }
+        Err(suggestions) => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                axum::Json(json!({"error": "file not found", "did_you_mean": suggestions})),
+            ))
+        }
+    };
+
+    if let Some(delay) = state.delays.get(&file) {
+        tokio::time::sleep(*delay).await;
+    }
+
+    let value = read_endpoint(state, &file, source.as_deref().unwrap_or(&file))?;
+    Ok((file, value))
+}
+
+// read a known endpoint's data from its own or a variant's file,
+// with masks and number handling applied
+fn read_endpoint(
+    state: &AppState,
+    file: &str,
+    source: &str,
+) -> Result<Value, (StatusCode, Json<Value>)> {
+    let path = data_file_path(&state.data_dir, source);
+
+    tracing::debug!("path: {}", path.display());
+    let options = ParseOptions {
+        default_format: state.fixture_format_default.as_deref(),
+        trailing_data: &state.trailing_data_policy,
+        encoding: &state.encoding,
+    };
+    let mut value = match read_data_file(&path, options) {
+        Ok(v) => v,
+        // the file was removed since the last scan, unlike a broken link
+        // below there's nothing left at the path at all
+        Err(DataFileError::Read(e))
+            if e.kind() == std::io::ErrorKind::NotFound && fs::symlink_metadata(&path).is_err() =>
+        {
+            return Err((
+                StatusCode::NOT_FOUND,
+                Json(json!({"error": "file not found"})),
+            ));
+        }
+        // read_to_string follows symlinks; a link that broke after startup ends up here
+        Err(DataFileError::Read(e)) => {
+            tracing::warn!("unable to read {}: {e}", path.display());
+            let mut body = json!({"error": "unable to read file"});
+            if state.verbose_errors {
+                body["detail"] = json!({"path": path, "message": e.to_string()});
+            }
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(body)));
+        }
+        Err(DataFileError::Parse {
+            error,
+            message,
+            line,
+            column,
+        }) => {
+            let mut body = json!({ "error": error });
+            if state.verbose_errors {
+                body["detail"] = json!({
+                    "path": path,
+                    "message": message,
+                    "line": line,
+                    "column": column,
+                });
+            }
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, Json(body)));
+        }
+    };
+
+    if !state.preserve_numbers {
+        normalize_numbers(&mut value);
+    }
+
+    if state.wrap_scalars && !value.is_array() && !value.is_object() {
+        value = Value::Array(vec![value]);
+    }
+
+    if let Some(fields) = state.masks.get(file) {
+        for field in fields {
+            let path: Vec<&str> = field.split('.').collect();
+            mask_field(&mut value, &path);
+        }
+    }
+
+    Ok(value)
+}
+
+// up to three endpoints within a small edit distance of the requested one
+fn suggest_endpoints(files: &[String], file: &str) -> Vec<String> {
+    let max_distance = 2.max(file.chars().count() / 3);
+    let mut close: Vec<(usize, &String)> = files
+        .iter()
+        .map(|f| (edit_distance(f, file), f))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    close.sort();
+    close.into_iter().take(3).map(|(_, f)| f.clone()).collect()
+}
+
+// levenshtein distance over chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// a record matches when every filtered field is present with an equal value,
+// a list of values matches a field equal to any one of them
+fn matches_filter(record: &Value, filter: &serde_json::Map<String, Value>) -> bool {
+    filter.iter().all(|(field, expected)| {
+        record.get(field).is_some_and(|actual| {
+            filter_value_matches(actual, expected)
+                || expected.as_array().is_some_and(|options| {
+                    options
+                        .iter()
+                        .any(|option| filter_value_matches(actual, option))
+                })
+        })
+    })
+}
+
+// equality that looks past how a number is written: a numeric field matches
+// an equal number or numeric string, a string field the text of a number
+fn filter_value_matches(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Number(a), Value::Number(b)) => numbers_equal(a, b),
+        (Value::Number(a), Value::String(b)) => b
+            .trim()
+            .parse::<serde_json::Number>()
+            .is_ok_and(|b| numbers_equal(a, &b)),
+        (Value::String(a), Value::Number(b)) => *a == b.to_string(),
+        _ => actual == expected,
+    }
+}
+
+// integers compare exactly by their digits whatever their size, anything
+// with a fraction or exponent as f64 so `30` equals `30.0`
+fn numbers_equal(a: &serde_json::Number, b: &serde_json::Number) -> bool {
+    if let (Some(a), Some(b)) = (integer_digits(a), integer_digits(b)) {
+        return a == b;
+    }
+    a.as_f64()
+        .zip(b.as_f64())
+        .is_some_and(|(a, b)| a.partial_cmp(&b) == Some(Ordering::Equal))
+}
+
+// sign and digits of a number written as a plain integer, without leading
+// zeros and with `-0` the same as `0`
+fn integer_digits(n: &serde_json::Number) -> Option<(bool, String)> {
+    let text = n.to_string();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.as_str()),
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let digits = digits.trim_start_matches('0');
+    Some((negative && !digits.is_empty(), digits.to_string()))
+}
+
+// sort by a field, records where it is null or missing go last or first
+// whichever the direction
+fn sort_records(records: &mut [Value], field: &str, descending: bool, nulls_first: bool) {
+    let nulls = if nulls_first {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    };
+    records.sort_by(|a, b| {
+        let a = a.get(field).filter(|value| !value.is_null());
+        let b = b.get(field).filter(|value| !value.is_null());
+        match (a, b) {
+            (Some(a), Some(b)) if descending => compare_values(b, a),
+            (Some(a), Some(b)) => compare_values(a, b),
+            (Some(_), None) => nulls.reverse(),
+            (None, Some(_)) => nulls,
+            (None, None) => Ordering::Equal,
+        }
+    });
+}
+
+// unset, empty and the usual "off" spellings count as false
+fn env_is_truthy(var: &str) -> bool {
+    std::env::var(var).is_ok_and(|value| {
+        !matches!(
+            value.to_lowercase().as_str(),
+            "" | "0" | "false" | "no" | "off"
+        )
+    })
+}
+
+// things --strict points out about a parsed data file that still loads fine
+fn strict_warnings(value: &Value) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Value::Array(records) = value {
+        let non_objects = records.iter().filter(|record| !record.is_object()).count();
+        if non_objects > 0 {
+            warnings.push(format!(
+                "{non_objects} of {} elements are not objects, record filters and ids won't apply to them",
+                records.len()
+            ));
+        }
+    }
+    warnings
+}
+
+// list the ids that occur more than once in an array collection,
+// files that can't be read or aren't arrays have nothing to report
+fn find_duplicate_ids(
+    data_dir: &str,
+    file: &str,
+    id_field: &str,
+    options: ParseOptions,
+) -> Vec<String> {
+    let Ok(Value::Array(records)) = read_data_file(&data_file_path(data_dir, file), options) else {
+        return Vec::new();
+    };
+
+    let mut seen = HashSet::new();
+    let mut duplicates = Vec::new();
+    for id in records.iter().filter_map(|record| record.get(id_field)) {
+        let id = id.to_string();
+        if !seen.insert(id.clone()) && !duplicates.contains(&id) {
+            duplicates.push(id);
+        }
+    }
+    duplicates
+}
+
+// serde_json keeps the original text of numbers (arbitrary_precision),
+// so reparse them as i64/u64/f64 the way a plain Value would hold them
+fn normalize_numbers(value: &mut Value) {
+    match value {
+        Value::Number(n) => {
+            if n.is_i64() || n.is_u64() {
+                return;
+            }
+            if let Some(f) = n.as_f64() {
+                *value = json!(f);
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(normalize_numbers),
+        Value::Object(map) => map.values_mut().for_each(normalize_numbers),
+        _ => {}
+    }
+}
+
+// run `edit` on the object holding the last key of a dot-separated path,
+// descending into every element when an array is hit on the way
+fn edit_field(
+    value: &mut Value,
+    path: &[&str],
+    edit: &impl Fn(&mut serde_json::Map<String, Value>, &str),
+) {
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                edit_field(item, path, edit);
+            }
+        }
+        Value::Object(map) => match path {
+            [] => {}
+            [key] => edit(map, key),
+            [key, rest @ ..] => {
+                if let Some(v) = map.get_mut(*key) {
+                    edit_field(v, rest, edit);
+                }
+            }
+        },
+        _ => {}
+    }
+}
+
+// replace the value at the dot-separated path with "***"
+fn mask_field(value: &mut Value, path: &[&str]) {
+    edit_field(value, path, &|map, key| {
+        if let Some(v) = map.get_mut(key) {
+            *v = json!("***");
+        }
+    });
+}
+
+// drop the field at the dot-separated path
+fn omit_field(value: &mut Value, path: &[&str]) {
+    edit_field(value, path, &|map, key| {
+        map.shift_remove(key);
+    });
+}
+
+// create a function that finds all data files in the directory data
+// and returns a vector of the endpoint names
+fn get_json_files(
+    data_dir: &str,
+    include_hidden: bool,
+    extensionless: bool,
+) -> std::io::Result<Vec<String>> {
+    let mut json_files = Vec::new();
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let known = match path.extension() {
+            Some(ext) => DATA_EXTENSIONS.iter().any(|known| ext == *known),
+            None => extensionless,
+        };
+        if !known {
+            continue;
+        }
+        // skip dotfiles like editor swap files or hidden backups unless asked for
+        if !include_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        // fs::metadata follows symlinks, so a broken link shows up as an error here
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => {}
+            Ok(_) => continue,
+            Err(e) => {
+                tracing::warn!("skipping unreadable file {}: {e}", path.display());
+                continue;
+            }
+        }
+        // trim of the extension
+        let file_name = path.file_stem().unwrap().to_str().unwrap().to_string();
+        if json_files.contains(&file_name) {
+            tracing::warn!(
+                "multiple data files for endpoint {file_name}, serving {}",
+                data_file_path(data_dir, &file_name).display()
+            );
+            continue;
+        }
+        json_files.push(file_name);
+    }
+    Ok(json_files)
+}
+
+// subdirectories named like `v1`, `v2`, ... in version order
+fn get_version_dirs(data_dir: &str) -> std::io::Result<Vec<String>> {
+    let mut versions: Vec<(u64, String)> = fs::read_dir(data_dir)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_str()?.to_string();
+            let number = name.strip_prefix('v')?.parse::<u64>().ok()?;
+            Some((number, name))
+        })
+        .collect();
+    versions.sort();
+    Ok(versions.into_iter().map(|(_, name)| name).collect())
+}
+
+// data file extensions in the order they are looked up for an endpoint
+const DATA_EXTENSIONS: [&str; 2] = ["json", "toml"];
+
+#[derive(Debug)]
+enum DataFileError {
+    Read(std::io::Error),
+    Parse {
+        /// Error as reported by the parser
+        error: String,
+        /// Short message without the location
+        message: String,
+        line: usize,
+        column: usize,
+    },
+}
+
+// resolve the file backing an endpoint, preferring .json when several exist
+// and falling back to a file without extension
+fn data_file_path(data_dir: &str, file: &str) -> PathBuf {
+    DATA_EXTENSIONS
+        .iter()
+        .map(|ext| PathBuf::from(format!("{data_dir}/{file}.{ext}")))
+        .find(|path| path.exists())
+        .or_else(|| Some(PathBuf::from(format!("{data_dir}/{file}"))).filter(|path| path.is_file()))
+        .unwrap_or_else(|| PathBuf::from(format!("{data_dir}/{file}.json")))
+}
+
+#[derive(Clone, Copy)]
+struct ParseOptions<'a> {
+    /// --fixture-format-default
+    default_format: Option<&'a str>,
+    /// --trailing-data-policy
+    trailing_data: &'a str,
+    /// --encoding
+    encoding: &'a str,
+}
+
+// read and parse a data file according to its extension, files with an
+// unknown or no extension use --fixture-format-default and else json
+fn read_data_file(path: &fsPath, options: ParseOptions) -> Result<Value, DataFileError> {
+    let str = read_text(path, options.encoding).map_err(DataFileError::Read)?;
+    let format = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if DATA_EXTENSIONS.contains(&ext) => ext,
+        _ => options.default_format.unwrap_or("json"),
+    };
+    tracing::debug!("parsing {} as {format}", path.display());
+
+    if format == "yaml" {
+        serde_yaml::from_str::<Value>(&str).map_err(|e| {
+            let (line, column) = e
+                .location()
+                .map_or((0, 0), |location| (location.line(), location.column()));
+            DataFileError::Parse {
+                error: e.to_string(),
+                message: e
+                    .to_string()
+                    .rsplit_once(" at line ")
+                    .map_or_else(|| e.to_string(), |(message, _)| message.to_string()),
+                line,
+                column,
+            }
+        })
+    } else if format == "toml" {
+        let table = toml::from_str::<toml::Table>(&str).map_err(|e| {
+            let (line, column) = line_column(&str, e.span().map_or(0, |span| span.start));
+            DataFileError::Parse {
+                error: e.to_string(),
+                message: e.message().to_string(),
+                line,
+                column,
+            }
+        })?;
+        // a file holding nothing but `[[records]]` is served as that array
+        if let Some(toml::Value::Array(records)) =
+            table.values().next().filter(|_| table.len() == 1)
+        {
+            if records.iter().all(toml::Value::is_table) {
+                return Ok(toml_to_json(toml::Value::Array(records.clone())));
+            }
+        }
+        Ok(toml_to_json(toml::Value::Table(table)))
+    } else {
+        let mut values = serde_json::Deserializer::from_str(&str).into_iter::<Value>();
+        let value = match options.trailing_data {
+            "first" => values
+                .next()
+                .unwrap_or_else(|| serde_json::from_str::<Value>(&str)),
+            // a file holding a single value is served as it is
+            "array" => values
+                .collect::<Result<Vec<_>, _>>()
+                .and_then(|mut all| match all.len() {
+                    0 => serde_json::from_str::<Value>(&str),
+                    1 => Ok(all.remove(0)),
+                    _ => Ok(Value::Array(all)),
+                }),
+            _ => serde_json::from_str::<Value>(&str),
+        };
+        value.map_err(|e| DataFileError::Parse {
+            error: e.to_string(),
+            message: e
+                .to_string()
+                .rsplit_once(" at line ")
+                .map_or_else(|| e.to_string(), |(message, _)| message.to_string()),
+            line: e.line(),
+            column: e.column(),
+        })
+    }
+}
+
+// read a file as text in the given --encoding, a byte order mark
+// overrides it and utf16 without one is taken as little endian
+fn read_text(path: &fsPath, encoding: &str) -> std::io::Result<String> {
+    let encoding = match encoding {
+        "utf16" => encoding_rs::UTF_16LE,
+        // the WHATWG latin1 is windows-1252, a superset of ISO-8859-1's printable range
+        "latin1" => encoding_rs::WINDOWS_1252,
+        _ => encoding_rs::UTF_8,
+    };
+    let bytes = fs::read(path)?;
+    let (text, used, had_errors) = encoding.decode(&bytes);
+    if had_errors {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("stream did not contain valid {}", used.name()),
+        ));
+    }
+    Ok(text.into_owned())
+}
+
+// 1-based line and column of a byte offset
+fn line_column(str: &str, offset: usize) -> (usize, usize) {
+    let before = &str[..offset.min(str.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+    (line, column)
+}
+
+// tables become objects and arrays of tables become arrays,
+// datetimes have no json equivalent so they are served as strings
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => json!(i),
+        toml::Value::Float(f) => json!(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(items) => Value::Array(items.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(k, v)| (k, toml_to_json(v)))
+                .collect(),
+        ),
+    }
+}
+
+// collect the unique values of `field` across all object records, sorted
+fn distinct_values(records: &[Value], field: &str) -> Value {
+    let mut values: Vec<Value> = Vec::new();
+    for value in records.iter().filter_map(|record| record.get(field)) {
+        if !values.contains(value) {
+            values.push(value.clone());
+        }
+    }
+    values.sort_by(compare_values);
+    Value::Array(values)
+}
+
+// order numbers numerically and strings lexically, everything else by its json text
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a fresh directory under the system temp dir, unique to this test run
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("json-server-rs-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    const OPTIONS: ParseOptions<'static> = ParseOptions {
+        default_format: None,
+        trailing_data: "error",
+        encoding: "utf8",
+    };
+
+    // write a data file and read it back the way an endpoint would
+    fn parse_file(
+        name: &str,
+        contents: &[u8],
+        options: ParseOptions,
+    ) -> Result<Value, DataFileError> {
+        let dir = test_dir(name);
+        let path = dir.join(format!("{name}.json"));
+        fs::write(&path, contents).unwrap();
+        let value = read_data_file(&path, options);
+        fs::remove_dir_all(dir).unwrap();
+        value
+    }
+
+    fn sorted_json_files(dir: &fsPath, include_hidden: bool) -> Vec<String> {
+        let mut files = get_json_files(dir.to_str().unwrap(), include_hidden, false).unwrap();
+        files.sort();
+        files
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_files_are_served_and_broken_links_skipped() {
+        let dir = test_dir("symlinks");
+        fs::write(dir.join("real.json"), r#"[{"id":1}]"#).unwrap();
+        std::os::unix::fs::symlink(dir.join("real.json"), dir.join("link.json")).unwrap();
+        std::os::unix::fs::symlink(dir.join("missing.json"), dir.join("broken.json")).unwrap();
+
+        assert_eq!(sorted_json_files(&dir, false), ["link", "real"]);
+        let link = data_file_path(dir.to_str().unwrap(), "link");
+        assert_eq!(read_data_file(&link, OPTIONS).unwrap(), json!([{"id": 1}]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dotfiles_are_hidden_unless_included() {
+        let dir = test_dir("hidden");
+        fs::write(dir.join("users.json"), "[]").unwrap();
+        fs::write(dir.join(".secret.json"), "[]").unwrap();
+
+        assert_eq!(sorted_json_files(&dir, false), ["users"]);
+        assert_eq!(sorted_json_files(&dir, true), [".secret", "users"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn large_integer_ids_round_trip_exactly() {
+        let json = r#"[{"id":98765432109876543210}]"#;
+        let value = parse_file("numbers", json.as_bytes(), OPTIONS).unwrap();
+        assert_eq!(serde_json::to_string(&value).unwrap(), json);
+
+        // without --preserve-numbers it is served as the nearest float
+        let mut value = value;
+        normalize_numbers(&mut value);
+        assert_eq!(value[0]["id"], json!(9.876_543_210_987_654e19));
+    }
+
+    #[test]
+    fn keys_keep_file_order_unless_sorted() {
+        let json = r#"{"b":1,"a":{"d":[{"z":1,"y":2}],"c":2}}"#;
+        let mut value = parse_file("keys", json.as_bytes(), OPTIONS).unwrap();
+        assert_eq!(serde_json::to_string(&value).unwrap(), json);
+
+        // what --sort-keys does to a response
+        value.sort_all_objects();
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"a":{"c":2,"d":[{"y":2,"z":1}]},"b":1}"#
+        );
+    }
+
+    #[test]
+    fn trailing_data_policies() {
+        let json = b"{\"a\":1}\n{\"b\":2}\n";
+        let policy = |trailing_data| ParseOptions {
+            trailing_data,
+            ..OPTIONS
+        };
+
+        let error = parse_file("trailing", json, policy("error")).unwrap_err();
+        assert!(matches!(error, DataFileError::Parse { line: 2, .. }));
+        assert_eq!(
+            parse_file("trailing", json, policy("first")).unwrap(),
+            json!({"a": 1})
+        );
+        assert_eq!(
+            parse_file("trailing", json, policy("array")).unwrap(),
+            json!([{"a": 1}, {"b": 2}])
+        );
+        // a single value is never wrapped
+        assert_eq!(
+            parse_file("trailing", b"[1]", policy("array")).unwrap(),
+            json!([1])
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn reads_stay_consistent_while_rescanning() {
+        let dir = test_dir("rescan");
+        fs::write(dir.join("users.json"), r#"[{"id":1}]"#).unwrap();
+        fs::write(dir.join("users.b.json"), r#"[{"id":2}]"#).unwrap();
+        let data_dir = dir.to_str().unwrap().to_string();
+        let args = Args::parse_from(["json-server-rs", "--data-dir", &data_dir]);
+        let (files, variants) = split_variants(&discover_files(&args, &data_dir).unwrap());
+        let state = Arc::new(app_state(&args, data_dir, files, variants));
+
+        // endpoints come and go between scans, the way a SIGHUP reload sees them
+        let rescans = {
+            let (dir, state) = (dir.clone(), state.clone());
+            tokio::task::spawn_blocking(move || {
+                for i in 0..300 {
+                    let extra = dir.join(format!("extra{}.json", i % 3));
+                    if extra.exists() {
+                        fs::remove_file(extra).unwrap();
+                    } else {
+                        // renamed into place so a read never sees it half written
+                        let partial = dir.join("extra.partial");
+                        fs::write(&partial, "[]").unwrap();
+                        fs::rename(partial, extra).unwrap();
+                    }
+                    rescan(&args, &state).unwrap();
+                }
+            })
+        };
+        let readers: Vec<_> = (0..8)
+            .map(|reader| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for _ in 0..300 {
+                        let (file, value) = load_endpoint(&state, "users", None).await.unwrap();
+                        assert_eq!((file.as_str(), value), ("users", json!([{"id": 1}])));
+                        let (file, value) =
+                            load_endpoint(&state, "users", Some("b")).await.unwrap();
+                        assert_eq!((file.as_str(), value), ("users", json!([{"id": 2}])));
+
+                        // an endpoint removed ahead of the rescan is a clean 404
+                        let extra = format!("extra{}", reader % 3);
+                        match load_endpoint(&state, &extra, None).await {
+                            Ok((_, value)) => assert_eq!(value, json!([])),
+                            Err((status, _)) => assert_eq!(status, StatusCode::NOT_FOUND),
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        rescans.await.unwrap();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn integers_beyond_u64_compare_exactly() {
+        let number = |text: &str| text.parse::<serde_json::Number>().unwrap();
+        let stored = number("12345678901234567890123");
+        assert!(numbers_equal(&stored, &number("12345678901234567890123")));
+        assert!(!numbers_equal(&stored, &number("12345678901234567890124")));
+        assert!(numbers_equal(&number("30"), &number("30.0")));
+        assert!(numbers_equal(&number("-0"), &number("0")));
+    }
+
+    #[test]
+    fn utf16_files_are_decoded() {
+        let json = r#"[{"name":"Zoë"}]"#;
+        let utf16le: Vec<u8> = json.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let utf16 = ParseOptions {
+            encoding: "utf16",
+            ..OPTIONS
+        };
+        assert_eq!(
+            parse_file("utf16", &utf16le, utf16).unwrap(),
+            json!([{"name": "Zoë"}])
+        );
+
+        // a byte order mark wins over the flag, the default utf8 included
+        let with_bom: Vec<u8> = [0xff, 0xfe].into_iter().chain(utf16le).collect();
+        assert_eq!(
+            parse_file("utf16-bom", &with_bom, OPTIONS).unwrap(),
+            json!([{"name": "Zoë"}])
+        );
+        let utf8_bom = [&[0xef, 0xbb, 0xbf], json.as_bytes()].concat();
+        assert_eq!(
+            parse_file("utf8-bom", &utf8_bom, OPTIONS).unwrap(),
+            json!([{"name": "Zoë"}])
+        );
+    }
+
+    #[test]
+    fn filters_match_string_and_json_numbers() {
+        let matches = |record: Value, filter: Value| {
+            let Value::Object(filter) = filter else {
+                unreachable!()
+            };
+            matches_filter(&record, &filter)
+        };
+        // a starwars-style string field holding digits
+        let luke = json!({"name": "Luke", "height": "172"});
+        assert!(matches(luke.clone(), json!({"height": 172})));
+        assert!(matches(luke.clone(), json!({"height": "172"})));
+        assert!(!matches(luke.clone(), json!({"height": 173})));
+        assert!(matches(luke.clone(), json!({"height": [150, 172]})));
+        assert!(!matches(luke, json!({"height": ["150", 173]})));
+
+        // a genuine json number
+        let ann = json!({"name": "Ann", "age": 30});
+        assert!(matches(ann.clone(), json!({"age": 30})));
+        assert!(matches(ann.clone(), json!({"age": 30.0})));
+        assert!(matches(ann.clone(), json!({"age": "30"})));
+        assert!(!matches(ann.clone(), json!({"age": "thirty"})));
+        assert!(matches(ann.clone(), json!({"age": ["29", 30.0]})));
+        assert!(!matches(ann, json!({"age": [29, "31"]})));
+    }
+
+    #[tokio::test]
+    async fn mocked_options_are_sent_as_written() {
+        use tower::ServiceExt;
+
+        let dir = test_dir("options-mock");
+        fs::write(dir.join("users.json"), "[]").unwrap();
+        let mocks = dir.join("mocks.json");
+        fs::write(&mocks, r#"{"OPTIONS /api/x": {"status": 404}}"#).unwrap();
+        let data_dir = dir.to_str().unwrap().to_string();
+        let args = Args::parse_from([
+            "json-server-rs",
+            "--data-dir",
+            &data_dir,
+            "--mock",
+            mocks.to_str().unwrap(),
+        ]);
+        let state = Arc::new(app_state(
+            &args,
+            data_dir,
+            vec!["users".into()],
+            HashSet::new(),
+        ));
+        let app = build_app(
+            Router::new().route("/api/:file", get(get_serve_json)),
+            state,
+        );
+
+        let options = |uri: &str| {
+            Request::builder()
+                .method(Method::OPTIONS)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let mocked = app.clone().oneshot(options("/api/x")).await.unwrap();
+        assert_eq!(mocked.status(), StatusCode::NOT_FOUND);
+        // without a mock the OPTIONS fallback still answers
+        let probed = app.oneshot(options("/api/users")).await.unwrap();
+        assert_eq!(probed.status(), StatusCode::NO_CONTENT);
+        assert_eq!(probed.headers()[header::ALLOW], "GET,HEAD,OPTIONS");
+        fs::remove_dir_all(dir).unwrap();
     }
-    Ok(json_files)
 }